@@ -0,0 +1,174 @@
+// Generates the named-character-reference trie used by `src/unescape.rs`.
+//
+// Reads the WHATWG named character reference table from `entities.json`
+// (name, including the leading `&` and, for most entries, a trailing `;`,
+// tab-separated from its expansion codepoints) and flattens it into a trie,
+// emitted as a static array into `$OUT_DIR/entities.rs`. Matching a named
+// reference then becomes a byte-by-byte walk of the trie instead of a set of
+// HashMap probes at decreasing candidate lengths.
+//
+// Each node's children occupy a contiguous, byte-sorted range of the emitted
+// array, so `EntityTrieNode::child()` can find an edge with a binary search.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+struct TrieNode {
+    children: BTreeMap<u8, TrieNode>,
+    expansion: Option<String>,
+}
+
+impl TrieNode {
+    fn new() -> Self {
+        TrieNode {
+            children: BTreeMap::new(),
+            expansion: None,
+        }
+    }
+
+    fn insert(&mut self, name: &[u8], expansion: String) {
+        let mut node = self;
+        for &byte in name {
+            node = node.children.entry(byte).or_insert_with(TrieNode::new);
+        }
+        node.expansion = Some(expansion);
+    }
+}
+
+fn parse_entities(raw: &str) -> TrieNode {
+    let mut root = TrieNode::new();
+
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, codepoints) = line
+            .split_once('\t')
+            .expect("entities.json line missing a name/codepoints separator");
+
+        // The trie is walked starting right after the input's leading '&'
+        // has already been consumed, so don't store it as part of the key.
+        let name = name.strip_prefix('&').expect("entity name missing '&'");
+
+        let expansion: String = codepoints
+            .split_whitespace()
+            .map(|cp| {
+                let cp = cp.strip_prefix("U+").expect("codepoint missing U+ prefix");
+                char::from_u32(u32::from_str_radix(cp, 16).expect("invalid codepoint"))
+                    .expect("invalid codepoint")
+            })
+            .collect();
+
+        root.insert(name.as_bytes(), expansion);
+    }
+
+    root
+}
+
+struct FlatNode<'a> {
+    byte: u8,
+    expansion: Option<&'a str>,
+    first_child: usize,
+    child_count: usize,
+}
+
+// Flatten the trie breadth-first, so each node's children land in a
+// contiguous, byte-sorted range of the result (`TrieNode.children` is a
+// `BTreeMap`, so it's already iterated in byte order). The root itself is
+// emitted as index 0 (its `byte` is unused) so that matching can always
+// start from `&ENTITY_TRIE[0]`.
+fn flatten(root: &TrieNode) -> Vec<FlatNode<'_>> {
+    let mut flat = vec![FlatNode {
+        byte: 0,
+        expansion: None,
+        first_child: 0,
+        child_count: 0,
+    }];
+    let mut queue = vec![(0usize, root)];
+    let mut i = 0;
+
+    while i < queue.len() {
+        let (self_index, node) = queue[i];
+        let first_child = flat.len();
+
+        for (&byte, child) in &node.children {
+            queue.push((flat.len(), child));
+            flat.push(FlatNode {
+                byte,
+                expansion: child.expansion.as_deref(),
+                first_child: 0,
+                child_count: 0,
+            });
+        }
+
+        flat[self_index].first_child = first_child;
+        flat[self_index].child_count = node.children.len();
+
+        i += 1;
+    }
+
+    flat
+}
+
+fn emit(flat: &[FlatNode]) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "struct EntityTrieNode {{").unwrap();
+    writeln!(out, "    byte: u8,").unwrap();
+    writeln!(out, "    expansion: Option<&'static [u8]>,").unwrap();
+    writeln!(out, "    first_child: u32,").unwrap();
+    writeln!(out, "    child_count: u32,").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "impl EntityTrieNode {{").unwrap();
+    writeln!(out, "    fn child(&self, byte: u8) -> Option<usize> {{").unwrap();
+    writeln!(out, "        let start = self.first_child as usize;").unwrap();
+    writeln!(out, "        let end = start + self.child_count as usize;").unwrap();
+    writeln!(
+        out,
+        "        ENTITY_TRIE[start..end].binary_search_by_key(&byte, |n| n.byte).ok().map(|i| start + i)"
+    )
+    .unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "static ENTITY_TRIE: &[EntityTrieNode] = &[").unwrap();
+
+    for node in flat {
+        let expansion = match node.expansion {
+            Some(s) => format!("Some(&{:?})", s.as_bytes()),
+            None => "None".to_string(),
+        };
+
+        writeln!(
+            out,
+            "    EntityTrieNode {{ byte: {byte}, expansion: {expansion}, \
+             first_child: {first_child}, child_count: {child_count} }},",
+            byte = node.byte,
+            first_child = node.first_child,
+            child_count = node.child_count,
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "];").unwrap();
+    out
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=entities.json");
+
+    let raw = fs::read_to_string("entities.json")
+        .expect("reading entities.json (the WHATWG named character reference table)");
+    let root = parse_entities(&raw);
+    let flat = flatten(&root);
+    let out = emit(&flat);
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("entities.rs"), out).unwrap();
+}
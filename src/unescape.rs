@@ -8,12 +8,12 @@
 // Some entities are prefixes for multiple other entities. For example:
 //   &times &times; &timesb; &timesbar; &timesd;
 
+use std::borrow::Cow;
 use std::char;
-use std::cmp::min;
 use std::iter::Peekable;
 use std::num::IntErrorKind;
 
-// Include the ENTITIES map generated by build.rs
+// Include the named-character-reference trie generated by build.rs.
 include!(concat!(env!("OUT_DIR"), "/entities.rs"));
 
 /// The context for an input string (requires `unescape` feature).
@@ -80,35 +80,284 @@ pub fn unescape_attribute<S: AsRef<[u8]>>(escaped: S) -> String {
 /// [algorithm described]: https://html.spec.whatwg.org/multipage/parsing.html#character-reference-state
 /// [named entities]: https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state
 pub fn unescape_in<S: AsRef<[u8]>>(escaped: S, context: Context) -> String {
+    let mut out = String::with_capacity(escaped.as_ref().len());
+    unescape_to(escaped, context, &mut out).expect("writing to a String cannot fail");
+    out
+}
+
+/// Expand all valid entities in a given context, writing the result directly
+/// to a [`std::fmt::Write`] sink (requires `unescape` feature).
+///
+/// This behaves exactly like [`unescape_in()`], but without the intermediate
+/// `String` allocation and `from_utf8` round-trip, so the unescaped output
+/// can be streamed straight into a buffer, file, or template a caller is
+/// already assembling. [`unescape_in()`] is implemented on top of this.
+pub fn unescape_to<S: AsRef<[u8]>, W: std::fmt::Write>(
+    escaped: S,
+    context: Context,
+    out: &mut W,
+) -> std::fmt::Result {
+    for chunk in unescape_chunks(escaped.as_ref(), context) {
+        out.write_str(bytes_to_str(&chunk))?;
+    }
+
+    Ok(())
+}
+
+/// Expand all valid entities in a given context, writing the result directly
+/// to a [`std::io::Write`] sink (requires `unescape` feature).
+///
+/// This is the [`std::io::Write`] counterpart to [`unescape_to()`], for
+/// streaming unescaped output straight into a file or socket.
+pub fn unescape_to_writer<S: AsRef<[u8]>, W: std::io::Write>(
+    escaped: S,
+    context: Context,
+    out: &mut W,
+) -> std::io::Result<()> {
+    for chunk in unescape_chunks(escaped.as_ref(), context) {
+        out.write_all(&chunk)?;
+    }
+
+    Ok(())
+}
+
+/// Walk `escaped`, yielding alternating literal and expanded-entity chunks.
+///
+/// Shared by [`unescape_to()`] and [`unescape_to_writer()`] so the two sinks
+/// can't drift apart on the scanning/entity-matching logic; each just picks
+/// how to write the chunks it gets.
+fn unescape_chunks(escaped: &[u8], context: Context) -> impl Iterator<Item = Cow<'_, [u8]>> {
+    let mut pos = 0;
+
+    std::iter::from_fn(move || {
+        if pos >= escaped.len() {
+            return None;
+        }
+
+        if escaped[pos] == b'&' {
+            pos += 1;
+
+            let mut rest = escaped[pos..].iter().copied().peekable();
+            let remaining = rest.len();
+            let expansion = match_entity(&mut rest, context, 0, None);
+            pos += remaining - rest.len();
+
+            Some(Cow::Owned(expansion))
+        } else {
+            let start = pos;
+            while pos < escaped.len() && escaped[pos] != b'&' {
+                pos += 1;
+            }
+
+            Some(Cow::Borrowed(&escaped[start..pos]))
+        }
+    })
+}
+
+fn bytes_to_str(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).expect("invalid UTF-8 produced while unescaping")
+}
+
+/// Expand all valid entities, without allocating if there is nothing to do
+/// (requires `unescape` feature).
+///
+/// This is appropriate to use on any text outside of an attribute. See
+/// [`unescape_cow_in()`] for more information.
+pub fn unescape_cow<S: AsRef<[u8]> + ?Sized>(escaped: &S) -> Cow<'_, str> {
+    unescape_cow_in(escaped, Context::General)
+}
+
+/// Expand all valid entities in an attribute, without allocating if there is
+/// nothing to do (requires `unescape` feature).
+///
+/// This is only appropriate for the value of an attribute. See
+/// [`unescape_cow_in()`] for more information.
+pub fn unescape_attribute_cow<S: AsRef<[u8]> + ?Sized>(escaped: &S) -> Cow<'_, str> {
+    unescape_cow_in(escaped, Context::Attribute)
+}
+
+/// Expand all valid entities in a given context, without allocating if there
+/// is nothing to do (requires `unescape` feature).
+///
+/// This behaves exactly like [`unescape_in()`], except that when `escaped`
+/// contains no `&` at all (the common case for most runs of text) and is
+/// valid UTF-8, the input is borrowed rather than copied into a new `String`.
+/// If `escaped` does contain an `&`, this falls back to allocating, starting
+/// the owned copy at the first `&` so the borrowed prefix still isn’t copied
+/// twice. If `escaped` is not valid UTF-8 at all, invalid byte sequences are
+/// lossily replaced (see [`String::from_utf8_lossy()`]) before either path
+/// runs, so this never panics on non-UTF-8 input the way [`unescape_in()`]
+/// does.
+pub fn unescape_cow_in<S: AsRef<[u8]> + ?Sized>(escaped: &S, context: Context) -> Cow<'_, str> {
     let escaped = escaped.as_ref();
-    let mut iter = escaped.iter().peekable();
 
-    // Most (all?) entities are longer than their expansion, so allocating the
-    // output buffer to be the same size as the input will usually prevent
-    // multiple allocations and generally won’t over-allocate by very much.
+    let text = match std::str::from_utf8(escaped) {
+        Ok(text) => text,
+        Err(_) => {
+            return Cow::Owned(unescape_in(
+                String::from_utf8_lossy(escaped).into_owned(),
+                context,
+            ))
+        }
+    };
+
+    let amp = match text.find('&') {
+        Some(amp) => amp,
+        None => return Cow::Borrowed(text),
+    };
+
+    let mut out = String::with_capacity(text.len());
+    out.push_str(&text[..amp]);
+    unescape_to(&text[amp..], context, &mut out).expect("writing to a String cannot fail");
+
+    Cow::Owned(out)
+}
+
+/// A lazy, streaming entity-unescaping adaptor (requires `unescape` feature).
+///
+/// Wraps any `Iterator<Item = u8>` and yields the unescaped bytes on demand,
+/// so arbitrarily large documents can be piped through without buffering the
+/// whole input or output. Use [`unescape_iter()`] to construct one.
+///
+/// ```rust
+/// use htmlize::*;
+///
+/// let unescaped: Vec<u8> = unescape_iter("&amp;times;".bytes(), Context::General).collect();
+/// assert_eq!(unescaped, b"&times;");
+/// ```
+pub struct Unescape<I: Iterator<Item = u8>> {
+    inner: Peekable<I>,
+    context: Context,
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl<I: Iterator<Item = u8>> Unescape<I> {
+    /// Wrap `inner` in a streaming unescaper using the given [`Context`].
+    pub fn new(inner: I, context: Context) -> Self {
+        Unescape {
+            inner: inner.peekable(),
+            context,
+            pending: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = u8>> Iterator for Unescape<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.pos >= self.pending.len() {
+            self.pending.clear();
+            self.pos = 0;
+
+            match self.inner.next() {
+                Some(b'&') => self.pending = match_entity(&mut self.inner, self.context, 0, None),
+                Some(c) => self.pending.push(c),
+                None => return None,
+            }
+        }
+
+        let byte = self.pending[self.pos];
+        self.pos += 1;
+        Some(byte)
+    }
+}
+
+/// Lazily expand all valid entities in a byte iterator (requires `unescape`
+/// feature).
+///
+/// Returns an [`Unescape`] iterator that yields the unescaped bytes of `iter`
+/// on demand. See [`unescape_in()`] for the expansion rules.
+pub fn unescape_iter<I: IntoIterator<Item = u8>>(
+    iter: I,
+    context: Context,
+) -> Unescape<I::IntoIter> {
+    Unescape::new(iter.into_iter(), context)
+}
+
+/// A WHATWG parse error recovered while unescaping (requires `unescape`
+/// feature).
+///
+/// These mirror the named variants of the [character reference state] that
+/// `unescape()` and friends recover from silently. [`unescape_with_errors()`]
+/// reports them instead of just recovering.
+///
+/// [character reference state]: https://html.spec.whatwg.org/multipage/parsing.html#character-reference-state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    MissingSemicolonAfterCharacterReference,
+    NullCharacterReference,
+    CharacterReferenceOutsideUnicodeRange,
+    SurrogateCharacterReference,
+    ControlCharacterReference,
+    NoncharacterCharacterReference,
+    AbsenceOfDigitsInNumericCharacterReference,
+    UnknownNamedCharacterReference,
+}
+
+/// A single recovered parse error, with its byte span in the original input
+/// (requires `unescape` feature).
+///
+/// See [`unescape_with_errors()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: std::ops::Range<usize>,
+}
+
+/// Expand all valid entities in a given context, recording every WHATWG parse
+/// error encountered along the way (requires `unescape` feature).
+///
+/// This expands entities exactly like [`unescape_in()`], but instead of only
+/// silently recovering from malformed references, it also returns a
+/// [`ParseError`] for each one, with a byte span into `escaped` so a caller
+/// can point back at the offending text. This makes it suitable as the
+/// backend for a conformance checker or linter, rather than just a
+/// transformer.
+pub fn unescape_with_errors<S: AsRef<[u8]>>(
+    escaped: S,
+    context: Context,
+) -> (String, Vec<ParseError>) {
+    let escaped = escaped.as_ref();
     let mut buffer = Vec::with_capacity(escaped.len());
+    let mut errors = Vec::new();
+    let mut pos = 0;
+
+    while pos < escaped.len() {
+        if escaped[pos] == b'&' {
+            let amp_pos = pos;
+            pos += 1;
+
+            let mut rest = escaped[pos..].iter().copied().peekable();
+            let remaining = rest.len();
+            let expansion = match_entity(&mut rest, context, amp_pos, Some(&mut errors));
+            pos += remaining - rest.len();
 
-    while let Some(c) = iter.next() {
-        if *c == b'&' {
-            let mut expansion = match_entity(&mut iter, context);
-            buffer.append(&mut expansion);
+            buffer.extend_from_slice(&expansion);
         } else {
-            buffer.push(*c);
+            buffer.push(escaped[pos]);
+            pos += 1;
         }
     }
 
-    String::from_utf8(buffer).unwrap()
+    (String::from_utf8(buffer).unwrap(), errors)
 }
 
 const PEEK_MATCH_ERROR: &str = "iter.next() did not match previous iter.peek()";
 
 #[allow(clippy::from_str_radix_10)]
-fn match_numeric_entity<'a, I>(iter: &mut Peekable<I>) -> Vec<u8>
+fn match_numeric_entity<I>(
+    iter: &mut Peekable<I>,
+    amp_pos: usize,
+    mut errors: Option<&mut Vec<ParseError>>,
+) -> Vec<u8>
 where
-    I: Iterator<Item = &'a u8>,
+    I: Iterator<Item = u8>,
 {
     let c = iter.next().expect(PEEK_MATCH_ERROR);
-    if *c != b'#' {
+    if c != b'#' {
         panic!("{}", PEEK_MATCH_ERROR);
     }
 
@@ -117,9 +366,21 @@ where
     let number = match iter.peek() {
         Some(&b'x') | Some(&b'X') => {
             // Hexadecimal entity
-            best_expansion.push(*iter.next().expect(PEEK_MATCH_ERROR));
+            best_expansion.push(iter.next().expect(PEEK_MATCH_ERROR));
 
             let hex = consume_hexadecimal(iter);
+            if hex.is_empty() {
+                // No digits at all were found, e.g. &#x; or &#x. The
+                // character-reference attempt is abandoned here, so it never
+                // reaches the missing-semicolon check below.
+                if let Some(errors) = errors.as_mut() {
+                    errors.push(ParseError {
+                        kind: ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference,
+                        span: amp_pos..amp_pos + best_expansion.len(),
+                    });
+                }
+                return best_expansion;
+            }
             best_expansion.extend_from_slice(&hex);
 
             u32::from_str_radix(&String::from_utf8(hex).unwrap(), 16)
@@ -127,37 +388,61 @@ where
         Some(_) => {
             // Presumably a decimal entity
             let dec = consume_decimal(iter);
+            if dec.is_empty() {
+                // No digits at all were found, e.g. &#; or &#a;.
+                if let Some(errors) = errors.as_mut() {
+                    errors.push(ParseError {
+                        kind: ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference,
+                        span: amp_pos..amp_pos + best_expansion.len(),
+                    });
+                }
+                return best_expansion;
+            }
             best_expansion.extend_from_slice(&dec);
 
             u32::from_str_radix(&String::from_utf8(dec).unwrap(), 10)
         }
         None => {
-            // Iterator reached end
+            // Iterator reached end, and no digits at all were found.
+            if let Some(errors) = errors.as_mut() {
+                errors.push(ParseError {
+                    kind: ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference,
+                    span: amp_pos..amp_pos + best_expansion.len(),
+                });
+            }
             return best_expansion;
         }
     };
 
     if let Some(&b';') = iter.peek() {
-        best_expansion.push(*iter.next().expect(PEEK_MATCH_ERROR));
-    } else {
-        // missing-semicolon-after-character-reference: ignore and continue.
+        best_expansion.push(iter.next().expect(PEEK_MATCH_ERROR));
+    } else if let Some(errors) = errors.as_mut() {
+        // missing-semicolon-after-character-reference.
         // https://html.spec.whatwg.org/multipage/parsing.html#parse-error-missing-semicolon-after-character-reference
+        errors.push(ParseError {
+            kind: ParseErrorKind::MissingSemicolonAfterCharacterReference,
+            span: amp_pos..amp_pos + best_expansion.len(),
+        });
     }
 
+    let span = amp_pos..amp_pos + best_expansion.len();
     match number {
         Ok(number) => {
-            if let Some(expansion) = correct_numeric_entity(number) {
+            if let Some(expansion) = correct_numeric_entity(number, span, errors.as_deref_mut()) {
                 return expansion;
             }
         }
         Err(error) => match error.kind() {
             IntErrorKind::PosOverflow => {
                 // Too large a number
+                if let Some(errors) = errors.as_mut() {
+                    errors.push(ParseError {
+                        kind: ParseErrorKind::CharacterReferenceOutsideUnicodeRange,
+                        span,
+                    });
+                }
                 return char_to_vecu8(REPLACEMENT_CHAR).unwrap();
             }
-            IntErrorKind::Empty => {
-                // No number, e.g. &#; or &#x;. Fall through.
-            }
             _ => panic!("error parsing number in numeric entity: {:?}", error),
         },
     }
@@ -185,6 +470,24 @@ fn is_surrogate<C: Into<u32>>(c: C) -> bool {
     (0xD800..=0xDFFF).contains(&c.into())
 }
 
+// https://infra.spec.whatwg.org/#noncharacter
+fn is_noncharacter(c: u32) -> bool {
+    (0xFDD0..=0xFDEF).contains(&c) || matches!(c & 0xFFFF, 0xFFFE | 0xFFFF)
+}
+
+// https://html.spec.whatwg.org/multipage/parsing.html#parse-error-control-character-reference
+fn is_control_character_reference(c: u32) -> bool {
+    fn is_control(c: u32) -> bool {
+        (0x00..=0x1F).contains(&c) || (0x7F..=0x9F).contains(&c)
+    }
+
+    fn is_ascii_whitespace(c: u32) -> bool {
+        matches!(c, 0x09 | 0x0A | 0x0C | 0x0D | 0x20)
+    }
+
+    c == 0x0D || (is_control(c) && !is_ascii_whitespace(c))
+}
+
 #[inline]
 fn char_to_vecu8(c: char) -> Option<Vec<u8>> {
     Some(c.to_string().into())
@@ -196,16 +499,44 @@ fn u32_to_vecu8(c: u32) -> Option<Vec<u8>> {
 }
 
 // https://html.spec.whatwg.org/multipage/parsing.html#numeric-character-reference-end-state
-fn correct_numeric_entity(number: u32) -> Option<Vec<u8>> {
+//
+// `span` and `errors` report each WHATWG parse error recovered from along the
+// way; pass `None` to recover silently without reporting anything.
+fn correct_numeric_entity(
+    number: u32,
+    span: std::ops::Range<usize>,
+    mut errors: Option<&mut Vec<ParseError>>,
+) -> Option<Vec<u8>> {
     match number {
-        // null-character-reference parse error:
-        0x00 => char_to_vecu8(REPLACEMENT_CHAR),
+        0x00 => {
+            if let Some(errors) = errors.as_mut() {
+                errors.push(ParseError {
+                    kind: ParseErrorKind::NullCharacterReference,
+                    span,
+                });
+            }
+            char_to_vecu8(REPLACEMENT_CHAR)
+        }
 
-        // character-reference-outside-unicode-range parse error:
-        c if is_outside_range(c) => char_to_vecu8(REPLACEMENT_CHAR),
+        c if is_outside_range(c) => {
+            if let Some(errors) = errors.as_mut() {
+                errors.push(ParseError {
+                    kind: ParseErrorKind::CharacterReferenceOutsideUnicodeRange,
+                    span,
+                });
+            }
+            char_to_vecu8(REPLACEMENT_CHAR)
+        }
 
-        // surrogate-character-reference parse error:
-        c if is_surrogate(c) => char_to_vecu8(REPLACEMENT_CHAR),
+        c if is_surrogate(c) => {
+            if let Some(errors) = errors.as_mut() {
+                errors.push(ParseError {
+                    kind: ParseErrorKind::SurrogateCharacterReference,
+                    span,
+                });
+            }
+            char_to_vecu8(REPLACEMENT_CHAR)
+        }
 
         // control-character-reference parse error exceptions:
         0x80 => u32_to_vecu8(0x20AC), // EURO SIGN (€)
@@ -236,36 +567,47 @@ fn correct_numeric_entity(number: u32) -> Option<Vec<u8>> {
         0x9E => u32_to_vecu8(0x017E), // LATIN SMALL LETTER Z WITH CARON (ž)
         0x9F => u32_to_vecu8(0x0178), // LATIN CAPITAL LETTER Y WITH DIAERESIS (Ÿ)
 
-        // A few parse errors and other cases are handled by the catch-all.
-        //
-        //   * noncharacter-character-reference parse error
-        //   * control-character-reference parse error
-        //   * 0x0d (carriage return)
-        //   * ASCII whitespace
-        //   * ASCII control characters
-        //
         // I found the spec a little confusing here, but a close reading and
-        // some browser testing convinced me that all of these cases are handled
-        // but just emitting the represented code point.
+        // some browser testing convinced me that noncharacter-character-
+        // reference, control-character-reference, 0x0d (carriage return),
+        // ASCII whitespace and other ASCII control characters are all
+        // handled but just emitting the represented code point, after
+        // reporting whichever of those first two parse errors applies.
+        c => {
+            if is_noncharacter(c) {
+                if let Some(errors) = errors.as_mut() {
+                    errors.push(ParseError {
+                        kind: ParseErrorKind::NoncharacterCharacterReference,
+                        span,
+                    });
+                }
+            } else if is_control_character_reference(c) {
+                if let Some(errors) = errors.as_mut() {
+                    errors.push(ParseError {
+                        kind: ParseErrorKind::ControlCharacterReference,
+                        span,
+                    });
+                }
+            }
 
-        // Everything else.
-        c => match char::from_u32(c) {
-            Some(c) => char_to_vecu8(c),
-            None => None,
-        },
+            match char::from_u32(c) {
+                Some(c) => char_to_vecu8(c),
+                None => None,
+            }
+        }
     }
 }
 
 macro_rules! consumer {
     ($name:ident, $($accept:pat)|+) => {
-        fn $name<'a, I>(iter: &mut Peekable<I>) -> Vec<u8>
-            where I: Iterator<Item = &'a u8>
+        fn $name<I>(iter: &mut Peekable<I>) -> Vec<u8>
+            where I: Iterator<Item = u8>
         {
             let mut buffer: Vec<u8> = Vec::new();
             while let Some(c) = iter.peek() {
-                match **c {
+                match *c {
                     $($accept)|+ => {
-                        buffer.push(*iter.next().expect(PEEK_MATCH_ERROR));
+                        buffer.push(iter.next().expect(PEEK_MATCH_ERROR));
                     },
                     _ => { return buffer; },
                 }
@@ -278,78 +620,122 @@ macro_rules! consumer {
 
 consumer!(consume_decimal, b'0'..=b'9');
 consumer!(consume_hexadecimal, b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F');
-consumer!(consume_alphanumeric, b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z');
 
-fn match_entity<'a, I>(iter: &mut Peekable<I>, context: Context) -> Vec<u8>
+// `amp_pos` and `errors` report each WHATWG parse error recovered from along
+// the way, with a byte span relative to `amp_pos` (the position of the `&`
+// that led here); pass `None` for `errors` to recover silently without
+// reporting anything, in which case `amp_pos` is unused.
+fn match_entity<I>(
+    iter: &mut Peekable<I>,
+    context: Context,
+    amp_pos: usize,
+    mut errors: Option<&mut Vec<ParseError>>,
+) -> Vec<u8>
 where
-    I: Iterator<Item = &'a u8>,
+    I: Iterator<Item = u8>,
 {
     if let Some(&b'#') = iter.peek() {
         // Numeric entity.
-        return match_numeric_entity(iter);
+        return match_numeric_entity(iter, amp_pos, errors);
     }
 
-    // Determine longest possible candidate including & and any trailing ;.
+    // Walk the named-character-reference trie one input byte at a time,
+    // buffering every consumed byte in `candidate`. If the walk runs off the
+    // trie (or the input ends) before confirming a match, or only confirms a
+    // shorter prefix of what was consumed, the unmatched tail of `candidate`
+    // is just replayed verbatim — this is how prefix entities like `&times`
+    // and `&timesb;` both resolve correctly.
     let mut candidate = vec![b'&'];
-    candidate.append(&mut consume_alphanumeric(iter));
+    let mut node = &ENTITY_TRIE[0];
+    let mut best: Option<(usize, &'static [u8])> = None;
+    // Whether any node visited names a real entity, even if the attribute
+    // termination rule below ends up rejecting it. Distinguishes "not a
+    // character reference at all" from "blocked by the attribute rule",
+    // since only the former is a genuine unknown-named-character-reference.
+    let mut saw_named_entity = false;
 
-    match iter.peek() {
-        Some(&b';') => {
-            // Actually consume the semicolon.
-            candidate.push(*iter.next().expect(PEEK_MATCH_ERROR));
-        }
-        Some(b'=') if context == Context::Attribute => {
-            // Special case, see https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state
-            // This character cannot be alphanumeric, since all alphanumeric
-            // characters were consumed above.
-            return candidate;
-        }
-        _ => {
-            // missing-semicolon-after-character-reference: ignore and continue.
-            // https://html.spec.whatwg.org/multipage/parsing.html#parse-error-missing-semicolon-after-character-reference
+    while let Some(&byte) = iter.peek() {
+        let child = match node.child(byte) {
+            Some(child) => child,
+            None => break,
+        };
+
+        candidate.push(iter.next().expect(PEEK_MATCH_ERROR));
+        node = &ENTITY_TRIE[child];
+
+        if let Some(expansion) = node.expansion {
+            saw_named_entity = true;
+
+            // In attributes, an entity without a trailing `;` only counts if
+            // it's not immediately followed by another alphanumeric or `=`.
+            // See https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state
+            let terminated = candidate.last() == Some(&b';')
+                || !matches!(iter.peek(), Some(&b) if b.is_ascii_alphanumeric() || b == b'=');
+
+            if context != Context::Attribute || terminated {
+                best = Some((candidate.len(), expansion));
+            }
         }
     }
 
-    if candidate.len() < ENTITY_MIN_LENGTH {
-        // Couldn’t possibly match.
-        return candidate;
-    }
+    match best {
+        Some((matched_len, expansion)) => {
+            if let Some(errors) = errors.as_mut() {
+                if candidate[..matched_len].last() != Some(&b';') {
+                    errors.push(ParseError {
+                        kind: ParseErrorKind::MissingSemicolonAfterCharacterReference,
+                        span: amp_pos..amp_pos + candidate.len(),
+                    });
+                }
+            }
+
+            let mut result =
+                Vec::with_capacity(expansion.len() + candidate.len() - matched_len);
+            result.extend_from_slice(expansion);
+
+            if matched_len < candidate.len() {
+                // Bytes consumed while probing for a longer entity that
+                // didn't pan out; replay them verbatim.
+                result.extend_from_slice(&candidate[matched_len..]);
+            }
 
-    if context == Context::Attribute {
-        // If candidate does not exactly match an entity, then don't expand it.
-        // This is because of the special case described in the spec (see
-        // https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state)
-        // Essentially it says that *in attributes* entities must be terminated
-        // with a semicolon, EOF, or some character *other* than [a-zA-Z0-9=].
-        //
-        // In other words, “&timesa” expands to “&timesa” in an attribute rather
-        // than “×a”.
-        if let Some(expansion) = ENTITIES.get(&candidate) {
-            return expansion.to_vec();
+            result
         }
-    } else {
-        // Find longest matching entity.
-        let max_len = min(candidate.len(), ENTITY_MAX_LENGTH);
-        for check_len in (ENTITY_MIN_LENGTH..=max_len).rev() {
-            if let Some(expansion) = ENTITIES.get(&candidate[..check_len]) {
-                // Found a match.
-                let mut result = Vec::with_capacity(
-                    expansion.len() + candidate.len() - check_len,
-                );
-                result.extend_from_slice(expansion);
-
-                if check_len < candidate.len() {
-                    // Need to append the rest of the consumed bytes.
-                    result.extend_from_slice(&candidate[check_len..]);
+        // Did not find a match. The trie walk above stops as soon as it runs
+        // off a known prefix, which may be well short of the full malformed
+        // reference, so widen `candidate` the rest of the way — matching
+        // what a full alphanumeric-run scan would have consumed — before
+        // reporting it as unknown.
+        None => {
+            if !saw_named_entity {
+                while let Some(&byte) = iter.peek() {
+                    if !byte.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    candidate.push(iter.next().expect(PEEK_MATCH_ERROR));
+                }
+                if let Some(&b';') = iter.peek() {
+                    candidate.push(iter.next().expect(PEEK_MATCH_ERROR));
                 }
 
-                return result;
+                // A lone `&` not followed by an alphanumeric or `#` isn't a
+                // character-reference attempt at all — it's just flushed, per
+                // https://html.spec.whatwg.org/multipage/parsing.html#character-reference-state
+                // — so only report it as unknown if something was actually
+                // consumed past the `&` itself.
+                if candidate.len() > 1 {
+                    if let Some(errors) = errors.as_mut() {
+                        errors.push(ParseError {
+                            kind: ParseErrorKind::UnknownNamedCharacterReference,
+                            span: amp_pos..amp_pos + candidate.len(),
+                        });
+                    }
+                }
             }
+
+            candidate
         }
     }
-
-    // Did not find a match.
-    candidate
 }
 
 #[cfg(test)]
@@ -469,4 +855,233 @@ mod tests {
     const ALL_EXPANDED: &str =
         include_str!("../tests/corpus/all-entities-expanded.txt");
     test_both!(all_entities, unescape(ALL_SOURCE) == ALL_EXPANDED);
+
+    fn collect_unescape_iter(input: &str, context: Context) -> String {
+        let bytes: Vec<u8> = unescape_iter(input.bytes(), context).collect();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn iter_matches_unescape() {
+        ::assert2::check!(
+            collect_unescape_iter("AND &amp;&AMP; and", Context::General) == "AND && and"
+        );
+    }
+
+    #[test]
+    fn iter_matches_unescape_attribute() {
+        ::assert2::check!(
+            collect_unescape_iter("&timesb", Context::Attribute) == "&timesb"
+        );
+    }
+
+    #[test]
+    fn iter_replays_unmatched_candidate_across_next_calls() {
+        // No entity named "time" exists, so every byte probed while trying
+        // to match one must come back out verbatim, one `next()` at a time.
+        ::assert2::check!(collect_unescape_iter("&time", Context::General) == "&time");
+    }
+
+    #[test]
+    fn iter_handles_entity_split_across_several_next_calls() {
+        let mut iter = unescape_iter("&timesb;rest".bytes(), Context::General);
+        let mut out = Vec::new();
+        while let Some(byte) = iter.next() {
+            out.push(byte);
+        }
+        ::assert2::check!(String::from_utf8(out).unwrap() == "\u{22a0}rest");
+    }
+
+    #[test]
+    fn cow_without_entity_borrows() {
+        match unescape_cow("no entities here") {
+            Cow::Borrowed(text) => ::assert2::check!(text == "no entities here"),
+            Cow::Owned(_) => panic!("expected a borrowed Cow"),
+        }
+    }
+
+    #[test]
+    fn cow_with_entity_owns() {
+        match unescape_cow("a &amp; b") {
+            Cow::Owned(text) => ::assert2::check!(text == "a & b"),
+            Cow::Borrowed(_) => panic!("expected an owned Cow"),
+        }
+    }
+
+    #[test]
+    fn attribute_cow_blocks_bare_entity() {
+        // Contains an `&`, so this still allocates, but the expansion itself
+        // must be blocked the same way `unescape_attribute()` blocks it.
+        ::assert2::check!(unescape_attribute_cow("&times=") == "&times=");
+    }
+
+    #[test]
+    fn cow_non_utf8_without_entity_is_lossy_not_a_panic() {
+        ::assert2::check!(unescape_cow(&b"\xff\xfe no entity"[..]) == "\u{fffd}\u{fffd} no entity");
+    }
+
+    #[test]
+    fn cow_non_utf8_with_entity_is_lossy_not_a_panic() {
+        ::assert2::check!(unescape_cow(&b"&amp;\xff"[..]) == "&\u{fffd}");
+    }
+
+    #[test]
+    fn errors_missing_semicolon() {
+        let (text, errors) = unescape_with_errors("&times a", Context::General);
+        ::assert2::check!(text == "× a");
+        ::assert2::check!(
+            errors
+                == vec![ParseError {
+                    kind: ParseErrorKind::MissingSemicolonAfterCharacterReference,
+                    span: 0..6,
+                }]
+        );
+    }
+
+    #[test]
+    fn errors_null_numeric_reference() {
+        let (text, errors) = unescape_with_errors("&#x0;", Context::General);
+        ::assert2::check!(text == "\u{fffd}");
+        ::assert2::check!(
+            errors
+                == vec![ParseError {
+                    kind: ParseErrorKind::NullCharacterReference,
+                    span: 0..5,
+                }]
+        );
+    }
+
+    #[test]
+    fn errors_unknown_named_reference_spans_whole_reference() {
+        // Regression test: the trie walk used to stop (and report) at the
+        // point where the candidate first diverged from a known prefix,
+        // rather than the full maximal alphanumeric run.
+        let (text, errors) = unescape_with_errors("&notanentity;", Context::General);
+        ::assert2::check!(text == "&notanentity;");
+        ::assert2::check!(
+            errors
+                == vec![ParseError {
+                    kind: ParseErrorKind::UnknownNamedCharacterReference,
+                    span: 0..13,
+                }]
+        );
+    }
+
+    #[test]
+    fn errors_blocked_attribute_entity_reports_nothing() {
+        let (text, errors) = unescape_with_errors("&times=", Context::Attribute);
+        ::assert2::check!(text == "&times=");
+        ::assert2::check!(errors == vec![]);
+    }
+
+    #[test]
+    fn errors_multiple_references_in_one_input() {
+        let (text, errors) = unescape_with_errors("&times a &#x0; &nope;", Context::General);
+        ::assert2::check!(text == "× a \u{fffd} &nope;");
+        ::assert2::check!(
+            errors
+                == vec![
+                    ParseError {
+                        kind: ParseErrorKind::MissingSemicolonAfterCharacterReference,
+                        span: 0..6,
+                    },
+                    ParseError {
+                        kind: ParseErrorKind::NullCharacterReference,
+                        span: 9..14,
+                    },
+                    ParseError {
+                        kind: ParseErrorKind::UnknownNamedCharacterReference,
+                        span: 15..21,
+                    },
+                ]
+        );
+    }
+
+    #[test]
+    fn errors_numeric_reference_with_no_digits_reports_once() {
+        // Absence-of-digits flushes and returns immediately per the WHATWG
+        // spec, so it must not also report a missing-semicolon error for the
+        // same reference.
+        let (text, errors) = unescape_with_errors("&#a;rest", Context::General);
+        ::assert2::check!(text == "&#a;rest");
+        ::assert2::check!(
+            errors
+                == vec![ParseError {
+                    kind: ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference,
+                    span: 0..2,
+                }]
+        );
+    }
+
+    #[test]
+    fn errors_hex_numeric_reference_with_no_digits_reports_once() {
+        let (text, errors) = unescape_with_errors("&#x;rest", Context::General);
+        ::assert2::check!(text == "&#x;rest");
+        ::assert2::check!(
+            errors
+                == vec![ParseError {
+                    kind: ParseErrorKind::AbsenceOfDigitsInNumericCharacterReference,
+                    span: 0..3,
+                }]
+        );
+    }
+
+    #[test]
+    fn errors_bare_ampersand_in_prose_reports_nothing() {
+        // A `&` not followed by an alphanumeric or `#` is not a
+        // character-reference attempt at all, per the WHATWG spec, so it
+        // must not be reported as an unknown named character reference.
+        let (text, errors) = unescape_with_errors("Fish & Chips", Context::General);
+        ::assert2::check!(text == "Fish & Chips");
+        ::assert2::check!(errors == vec![]);
+    }
+
+    #[test]
+    fn errors_multiple_bare_ampersands_report_nothing() {
+        let (text, errors) = unescape_with_errors("a & b & c", Context::General);
+        ::assert2::check!(text == "a & b & c");
+        ::assert2::check!(errors == vec![]);
+    }
+
+    // The `times`/`timesb`/`timesbar`/`timesd` family (see the module-level
+    // comment) all share a trie prefix, so they exercise the trie walk
+    // picking the longest match rather than stopping at the first node with
+    // an expansion.
+    test_both!(trie_sibling_times, unescape("&times;") == "×");
+    test_both!(trie_sibling_timesb, unescape("&timesb;") == "⊠");
+    test_both!(trie_sibling_timesbar, unescape("&timesbar;") == "⧱");
+    test_both!(trie_sibling_timesd, unescape("&timesd;") == "⨰");
+
+    test!(
+        trie_sibling_timesbar_falls_back_to_times_without_semicolon,
+        unescape("&timesbarrel") == "×barrel"
+    );
+
+    #[test]
+    fn to_matches_unescape_in() {
+        let mut out = String::new();
+        unescape_to("AND &amp;&AMP; and", Context::General, &mut out).unwrap();
+        ::assert2::check!(out == unescape("AND &amp;&AMP; and"));
+    }
+
+    #[test]
+    fn to_respects_attribute_context() {
+        let mut out = String::new();
+        unescape_to("&timesb", Context::Attribute, &mut out).unwrap();
+        ::assert2::check!(out == "&timesb");
+    }
+
+    #[test]
+    fn to_writer_matches_unescape_in() {
+        let mut out = Vec::new();
+        unescape_to_writer("AND &amp;&AMP; and", Context::General, &mut out).unwrap();
+        ::assert2::check!(String::from_utf8(out).unwrap() == unescape("AND &amp;&AMP; and"));
+    }
+
+    #[test]
+    fn to_writer_respects_attribute_context() {
+        let mut out = Vec::new();
+        unescape_to_writer("&timesb", Context::Attribute, &mut out).unwrap();
+        ::assert2::check!(String::from_utf8(out).unwrap() == "&timesb");
+    }
 }